@@ -15,15 +15,27 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+extern crate ignore;
 extern crate notify;
+extern crate notify_rust;
 extern crate structopt;
+#[cfg(unix)]
+extern crate libc;
 
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::overrides::{Override, OverrideBuilder};
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::PathBuf;
-use std::process::{exit, Command};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use notify_rust::Notification;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(Debug, Clone)]
@@ -38,10 +50,188 @@ enum CommandParseError {
     EmptyCommand,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnBusy {
+    Queue,
+    DoNothing,
+    Restart,
+    Signal,
+}
+
+#[derive(Debug)]
+struct ParseOnBusyError(String);
+
+impl fmt::Display for ParseOnBusyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown on-busy policy: {}", self.0)
+    }
+}
+
+impl FromStr for OnBusy {
+    type Err = ParseOnBusyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(OnBusy::Queue),
+            "do-nothing" => Ok(OnBusy::DoNothing),
+            "restart" => Ok(OnBusy::Restart),
+            "signal" => Ok(OnBusy::Signal),
+            _ => Err(ParseOnBusyError(s.into())),
+        }
+    }
+}
+
+// A signal to send to a child process group, by name (e.g. "SIGTERM").
+// On non-unix platforms no signal can be delivered, so any name parses
+// but is simply ignored in favor of `Child::kill`.
+#[derive(Debug, Clone, Copy)]
+struct Signal(#[cfg(unix)] i32);
+
+#[derive(Debug)]
+struct ParseSignalError(String);
+
+impl fmt::Display for ParseSignalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown signal: {}", self.0)
+    }
+}
+
+impl FromStr for Signal {
+    type Err = ParseSignalError;
+
+    #[cfg(unix)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let signal = match s {
+            "SIGHUP" => libc::SIGHUP,
+            "SIGINT" => libc::SIGINT,
+            "SIGQUIT" => libc::SIGQUIT,
+            "SIGKILL" => libc::SIGKILL,
+            "SIGTERM" => libc::SIGTERM,
+            "SIGUSR1" => libc::SIGUSR1,
+            "SIGUSR2" => libc::SIGUSR2,
+            _ => return Err(ParseSignalError(s.into())),
+        };
+        Ok(Signal(signal))
+    }
+
+    #[cfg(not(unix))]
+    fn from_str(_s: &str) -> Result<Self, Self::Err> {
+        Ok(Signal())
+    }
+}
+
+// Spawns children into their own process group on unix so that a stop
+// signal delivered to the group reaches the whole subtree, not just the
+// direct child. There is no equivalent on Windows.
+#[cfg(unix)]
+fn spawn_in_process_group(command: &mut Command) -> std::io::Result<Child> {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+    command.spawn()
+}
+
+#[cfg(not(unix))]
+fn spawn_in_process_group(command: &mut Command) -> std::io::Result<Child> {
+    command.spawn()
+}
+
+#[cfg(unix)]
+fn send_signal(child: &Child, signal: Signal) {
+    unsafe {
+        libc::kill(-(child.id() as i32), signal.0);
+    }
+}
+
+#[cfg(not(unix))]
+fn send_signal(_child: &Child, _signal: Signal) {}
+
+// Asks `child` to exit via `signal`, giving it up to `timeout` to do so
+// before escalating to SIGKILL (or `Child::kill` on Windows, where no
+// signal was actually delivered above).
+fn stop_child(child: &mut Child, signal: Signal, timeout: Duration) {
+    send_signal(child, signal);
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) | Err(_) => return,
+            Ok(None) => {
+                if Instant::now() >= deadline {
+                    let _ = child.kill();
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+// Modeled on watchexec's Shell: when set to anything but `None`, commands
+// are handed to the named shell as a single string instead of being
+// exec'd directly, so pipes, redirects and globbing work.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shell {
+    Unix,
+    Powershell,
+    Cmd,
+    None,
+}
+
+#[derive(Debug)]
+struct ParseShellError(String);
+
+impl fmt::Display for ParseShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown shell: {}", self.0)
+    }
+}
+
+impl FromStr for Shell {
+    type Err = ParseShellError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unix" => Ok(Shell::Unix),
+            "powershell" => Ok(Shell::Powershell),
+            "cmd" => Ok(Shell::Cmd),
+            "none" => Ok(Shell::None),
+            _ => Err(ParseShellError(s.into())),
+        }
+    }
+}
+
+// The kind of filesystem change that triggered a rebuild, exposed to
+// commands as `{event}`/$REBUILD_EVENT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EventKind {
+    Write,
+    Create,
+    Remove,
+    Rename,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EventKind::Write => "write",
+            EventKind::Create => "create",
+            EventKind::Remove => "remove",
+            EventKind::Rename => "rename",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct SimpleCommand {
     command: String,
     args: Vec<String>,
+    // The command line as originally written, space-joined, for use
+    // when `--shell` hands it to a shell as a single argument.
+    raw: String,
+    // REBUILD_* variables to set on the spawned command, populated by
+    // `apply_event`.
+    env: Vec<(String, String)>,
     proceed_if: ProceedIf,
 }
 
@@ -56,31 +246,141 @@ impl SimpleCommand {
 
         let command = String::from(&command_line[0]);
         let args = command_line[1..command_line.len()].to_vec();
+        let raw = command_line.join(" ");
 
         Ok(SimpleCommand {
             command,
             args,
+            raw,
+            env: Vec::new(),
             proceed_if,
         })
     }
 
-    fn set_filename(&mut self, path: &str) {
+    // Replaces the legacy `{}` placeholder along with `{path}`, `{name}`,
+    // `{dir}`, `{ext}` and `{event}` in the command line, and records the
+    // matching $REBUILD_* environment variables for `execute` to apply.
+    fn apply_event(&mut self, path: &str, event: EventKind) {
+        let as_path = Path::new(path);
+        let name = as_path
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dir = as_path
+            .parent()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let ext = as_path
+            .extension()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let event_name = event.to_string();
+
+        let substitute = |s: &str| {
+            s.replace("{path}", path)
+                .replace("{name}", &name)
+                .replace("{dir}", &dir)
+                .replace("{ext}", &ext)
+                .replace("{event}", &event_name)
+                .replace("{}", path)
+        };
+
         for i in 0..self.args.len() {
-            self.args[i] = self.args[i].replace("{}", path);
+            self.args[i] = substitute(&self.args[i]);
         }
+        self.raw = substitute(&self.raw);
+
+        self.env = vec![
+            ("REBUILD_PATH".into(), path.to_string()),
+            ("REBUILD_NAME".into(), name),
+            ("REBUILD_DIR".into(), dir),
+            ("REBUILD_EXT".into(), ext),
+            ("REBUILD_EVENT".into(), event_name),
+        ];
     }
 
-    fn execute(&self) -> bool {
-        match Command::new(&self.command).args(&self.args).status() {
-            Ok(status) => match self.proceed_if {
-                ProceedIf::Any => true,
-                ProceedIf::Success => status.success(),
-                ProceedIf::Failure => !status.success(),
-            },
+    fn build_command(&self, shell: Shell) -> Command {
+        let mut command = match shell {
+            Shell::None => {
+                let mut command = Command::new(&self.command);
+                command.args(&self.args);
+                command
+            }
+            Shell::Unix => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(&self.raw);
+                command
+            }
+            Shell::Powershell => {
+                let mut command = Command::new("powershell");
+                command.arg("-Command").arg(&self.raw);
+                command
+            }
+            Shell::Cmd => {
+                let mut command = Command::new("cmd");
+                command.arg("/C").arg(&self.raw);
+                command
+            }
+        };
+
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+
+        command
+    }
+
+    // Spawns the command and blocks until it finishes, polling
+    // `current_child` in small increments so that a busy-policy handler
+    // running on another thread can reach in and kill it mid-flight
+    // (see OnBusy::Restart). Returns (proceed, succeeded): `proceed` says
+    // whether the pipeline should move on to the next command per this
+    // command's `ProceedIf`, while `succeeded` is this command's actual
+    // exit status, independent of that decision (a `||` command that
+    // succeeds should still report success even though it tells the
+    // pipeline to stop).
+    fn execute(&self, current_child: &Arc<Mutex<Option<Child>>>, shell: Shell) -> (bool, bool) {
+        let mut command = self.build_command(shell);
+        let child = match spawn_in_process_group(&mut command) {
+            Ok(child) => child,
             Err(why) => {
                 eprintln!("Error: Failed to execute command: {}", why);
-                false
+                return (false, false);
+            }
+        };
+        *current_child.lock().unwrap() = Some(child);
+
+        let status = loop {
+            {
+                let mut guard = current_child.lock().unwrap();
+                match guard.as_mut() {
+                    Some(child) => match child.try_wait() {
+                        Ok(Some(status)) => break Some(status),
+                        Ok(None) => (),
+                        Err(why) => {
+                            eprintln!("Error: Failed to wait for command: {}", why);
+                            break None;
+                        }
+                    },
+                    // Killed out from under us by an OnBusy::Restart handler.
+                    None => break None,
+                }
             }
+            thread::sleep(Duration::from_millis(50));
+        };
+        *current_child.lock().unwrap() = None;
+
+        match status {
+            Some(status) => {
+                let succeeded = status.success();
+                let proceed = match self.proceed_if {
+                    ProceedIf::Any => true,
+                    ProceedIf::Success => succeeded,
+                    ProceedIf::Failure => !succeeded,
+                };
+                (proceed, succeeded)
+            }
+            None => (false, false),
         }
     }
 }
@@ -89,10 +389,15 @@ impl SimpleCommand {
 struct RebuildConfig {
     commands: Vec<SimpleCommand>,
     verbatim: bool,
+    shell: Shell,
 }
 
 impl RebuildConfig {
-    fn new(cmdline: Vec<String>, verbatim: bool) -> Result<RebuildConfig, CommandParseError> {
+    fn new(
+        cmdline: Vec<String>,
+        verbatim: bool,
+        shell: Shell,
+    ) -> Result<RebuildConfig, CommandParseError> {
         let mut commands = Vec::<SimpleCommand>::new();
 
         let mut single_command = Vec::<String>::new();
@@ -126,16 +431,20 @@ impl RebuildConfig {
             }
         }
 
-        Ok(RebuildConfig { commands, verbatim })
+        Ok(RebuildConfig {
+            commands,
+            verbatim,
+            shell,
+        })
     }
 
-    fn set_filename(&self, path: PathBuf) -> RebuildConfig {
+    fn for_event(&self, path: PathBuf, event: EventKind) -> RebuildConfig {
         let mut out = self.clone();
 
         if !self.verbatim {
             let path = path.as_os_str().to_string_lossy().into_owned();
             for i in 0..out.commands.len() {
-                out.commands[i].set_filename(&path);
+                out.commands[i].apply_event(&path, event);
             }
         }
 
@@ -143,12 +452,91 @@ impl RebuildConfig {
     }
 }
 
-fn rebuild_sync(config: RebuildConfig) {
+// The exit status of the last command the pipeline actually ran, not
+// whether it ran to completion: e.g. `a || b` where `a` succeeds stops
+// after `a` (by design) but should still report success, while a
+// pipeline cut short by a kill (see OnBusy::Restart) reports failure.
+fn rebuild_sync(config: RebuildConfig, current_child: &Arc<Mutex<Option<Child>>>) -> bool {
+    let mut succeeded = true;
     for cmd in config.commands.iter() {
-        if !cmd.execute() {
+        let (proceed, this_succeeded) = cmd.execute(current_child, config.shell);
+        succeeded = this_succeeded;
+        if !proceed {
             break;
         }
     }
+    succeeded
+}
+
+// Modeled on watchexec's --clear: `Clear` wipes the visible screen and
+// scrollback, `Reset` additionally resets terminal state (RIS).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClearMode {
+    Clear,
+    Reset,
+}
+
+#[derive(Debug)]
+struct ParseClearModeError(String);
+
+impl fmt::Display for ParseClearModeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown clear mode: {}", self.0)
+    }
+}
+
+impl FromStr for ClearMode {
+    type Err = ParseClearModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clear" => Ok(ClearMode::Clear),
+            "reset" => Ok(ClearMode::Reset),
+            _ => Err(ParseClearModeError(s.into())),
+        }
+    }
+}
+
+fn clear_screen(mode: ClearMode) {
+    match mode {
+        ClearMode::Clear => print!("\x1B[2J\x1B[3J\x1B[H"),
+        ClearMode::Reset => print!("\x1Bc"),
+    }
+    let _ = io::stdout().flush();
+}
+
+fn notify_result(success: bool) {
+    let (summary, body) = if success {
+        ("rebuild: success", "The command pipeline finished successfully.")
+    } else {
+        ("rebuild: failed", "The command pipeline finished with a failing command.")
+    };
+
+    if let Err(why) = Notification::new().summary(summary).body(body).show() {
+        eprintln!("Warning: Failed to show desktop notification: {}", why);
+    }
+}
+
+// Runs the clear/notify side effects around a single `rebuild_sync`
+// call, so every call site (sync, async, and the busy-policy manager)
+// gets the same behavior.
+fn run_pipeline(
+    config: RebuildConfig,
+    current_child: &Arc<Mutex<Option<Child>>>,
+    clear: Option<ClearMode>,
+    notify: bool,
+) -> bool {
+    if let Some(mode) = clear {
+        clear_screen(mode);
+    }
+
+    let success = rebuild_sync(config, current_child);
+
+    if notify {
+        notify_result(success);
+    }
+
+    success
 }
 
 enum ThreadHandleMessage {
@@ -159,16 +547,19 @@ enum ThreadHandleMessage {
 fn do_rebuild(
     config: RebuildConfig,
     run_async: bool,
+    clear: Option<ClearMode>,
+    notify: bool,
     thread_handle_sender: &Sender<ThreadHandleMessage>,
 ) {
     if run_async {
+        let current_child = Arc::new(Mutex::new(None));
         thread_handle_sender
             .send(ThreadHandleMessage::Handle(thread::spawn(move || {
-                rebuild_sync(config)
+                run_pipeline(config, &current_child, clear, notify);
             })))
             .unwrap();
     } else {
-        rebuild_sync(config);
+        run_pipeline(config, &Arc::new(Mutex::new(None)), clear, notify);
     }
 }
 
@@ -182,10 +573,178 @@ fn prepare_manager_thread(receiver: Receiver<ThreadHandleMessage>) -> JoinHandle
     })
 }
 
+// Coordinates at most one running rebuild pipeline and arbitrates what
+// happens to events that arrive while it is still running, according to
+// the configured `OnBusy` policy. Duplicate events that arrive while a
+// rebuild is live collapse into a single pending run rather than piling
+// up concurrent pipelines.
+struct RebuildManager {
+    on_busy: OnBusy,
+    stop_signal: Signal,
+    stop_timeout: Duration,
+    clear: Option<ClearMode>,
+    notify: bool,
+    running: Mutex<bool>,
+    current_child: Arc<Mutex<Option<Child>>>,
+    pending: Mutex<Option<RebuildConfig>>,
+}
+
+impl RebuildManager {
+    fn new(
+        on_busy: OnBusy,
+        stop_signal: Signal,
+        stop_timeout: Duration,
+        clear: Option<ClearMode>,
+        notify: bool,
+    ) -> RebuildManager {
+        RebuildManager {
+            on_busy,
+            stop_signal,
+            stop_timeout,
+            clear,
+            notify,
+            running: Mutex::new(false),
+            current_child: Arc::new(Mutex::new(None)),
+            pending: Mutex::new(None),
+        }
+    }
+
+    fn submit(
+        self: &Arc<Self>,
+        config: RebuildConfig,
+        thread_handle_sender: &Sender<ThreadHandleMessage>,
+    ) {
+        let mut running = self.running.lock().unwrap();
+        if *running {
+            match self.on_busy {
+                OnBusy::DoNothing => (),
+                OnBusy::Queue => *self.pending.lock().unwrap() = Some(config),
+                OnBusy::Restart => {
+                    *self.pending.lock().unwrap() = Some(config);
+                    // Take the child out (rather than just killing it in
+                    // place) so `execute`'s poll loop observes `None` and
+                    // aborts the whole pipeline instead of reading the
+                    // exit status of the command we just killed.
+                    let mut child = self.current_child.lock().unwrap().take();
+                    if let Some(child) = child.as_mut() {
+                        stop_child(child, self.stop_signal, self.stop_timeout);
+                    }
+                }
+                OnBusy::Signal => {
+                    if let Some(child) = self.current_child.lock().unwrap().as_ref() {
+                        send_signal(child, self.stop_signal);
+                    }
+                }
+            }
+            return;
+        }
+
+        *running = true;
+        drop(running);
+
+        let manager = Arc::clone(self);
+        thread_handle_sender
+            .send(ThreadHandleMessage::Handle(thread::spawn(move || {
+                manager.run_until_dry(config)
+            })))
+            .unwrap();
+    }
+
+    fn run_until_dry(&self, mut config: RebuildConfig) {
+        loop {
+            run_pipeline(config, &self.current_child, self.clear, self.notify);
+
+            // Hold `running` across the pending check so a `submit` call
+            // can't slip a config into `pending` in the gap between us
+            // draining it and clearing `running` — otherwise that config
+            // would never run.
+            let mut running = self.running.lock().unwrap();
+            match self.pending.lock().unwrap().take() {
+                Some(next) => {
+                    drop(running);
+                    config = next;
+                }
+                None => {
+                    *running = false;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Decides whether a changed path should trigger a rebuild: paths
+// excluded by `.gitignore`/`.ignore` are always skipped, then
+// --include/--exclude globs are consulted (--include, if given at all,
+// makes matching one of them mandatory).
+struct PathFilter {
+    gitignore: Gitignore,
+    overrides: Override,
+    has_includes: bool,
+}
+
+impl PathFilter {
+    // `is_dir_watch` should be false when watching a single file: in that
+    // case the watched file itself is whatever the user explicitly asked
+    // for, so it shouldn't be second-guessed by .gitignore/.ignore (a
+    // `*.log` watch target would otherwise never trigger).
+    fn build(root: &Path, include: &[String], exclude: &[String], is_dir_watch: bool) -> PathFilter {
+        let mut gitignore_builder = GitignoreBuilder::new(root);
+        if is_dir_watch {
+            let _ = gitignore_builder.add(root.join(".gitignore"));
+            let _ = gitignore_builder.add(root.join(".ignore"));
+        }
+        let gitignore = gitignore_builder.build().unwrap_or_else(|_| Gitignore::empty());
+
+        let mut overrides_builder = OverrideBuilder::new(root);
+        let mut has_includes = false;
+        for pattern in include {
+            if overrides_builder.add(pattern).is_ok() {
+                has_includes = true;
+            }
+        }
+        for pattern in exclude {
+            let _ = overrides_builder.add(&format!("!{}", pattern));
+        }
+        let overrides = overrides_builder.build().unwrap_or_else(|_| Override::empty());
+
+        PathFilter {
+            gitignore,
+            overrides,
+            has_includes,
+        }
+    }
+
+    fn should_trigger(&self, path: &Path) -> bool {
+        let is_dir = path.is_dir();
+
+        // Checking the parents too makes files inside an ignored directory
+        // (e.g. `target/`) count as ignored; the lookup panics on paths
+        // outside the gitignore's root, so those are not checked.
+        if path.starts_with(self.gitignore.path())
+            && self
+                .gitignore
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+        {
+            return false;
+        }
+
+        match self.overrides.matched(path, is_dir) {
+            ignore::Match::Whitelist(_) => true,
+            ignore::Match::Ignore(_) => false,
+            ignore::Match::None => !self.has_includes,
+        }
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(about = "Run command automatically when specified file is updated.")]
 struct Opt {
-    #[structopt(long = "verbatim", help = "Don't replace '{}' with changed filename")]
+    #[structopt(
+        long = "verbatim",
+        help = "Don't replace '{}', '{path}', '{name}', '{dir}', '{ext}' or '{event}' in the command with details of the changed file"
+    )]
     verbatim: bool,
     #[structopt(
         long = "do-while",
@@ -194,7 +753,62 @@ struct Opt {
     init: bool,
     #[structopt(long = "async", help = "Runs command asynchronously")]
     run_async: bool,
-    #[structopt(name = "filename", help = "Filename to watch", required = true)]
+    #[structopt(
+        long = "on-busy",
+        help = "What to do when a rebuild is triggered while one is already running: queue, do-nothing, restart, signal (requires --async)",
+        default_value = "queue"
+    )]
+    on_busy: OnBusy,
+    #[structopt(
+        long = "stop-signal",
+        help = "Signal sent to a running pipeline's process group before replacing it (unix only; ignored elsewhere, where Child::kill is used instead)",
+        default_value = "SIGTERM"
+    )]
+    stop_signal: Signal,
+    #[structopt(
+        long = "stop-timeout",
+        help = "Milliseconds to wait for --stop-signal to take effect before sending SIGKILL",
+        default_value = "2000"
+    )]
+    stop_timeout: u64,
+    #[structopt(
+        long = "include",
+        help = "Only trigger rebuilds for changed paths matching this glob (can be repeated)"
+    )]
+    include: Vec<String>,
+    #[structopt(
+        long = "exclude",
+        help = "Never trigger rebuilds for changed paths matching this glob (can be repeated)"
+    )]
+    exclude: Vec<String>,
+    #[structopt(
+        long = "shell",
+        help = "Run the command through a shell instead of exec'ing it directly: unix, powershell, cmd, none",
+        default_value = "none"
+    )]
+    shell: Shell,
+    #[structopt(
+        long = "clear",
+        help = "Clear the terminal before each rebuild: clear (wipe screen and scrollback) or reset (full terminal reset)",
+        possible_values = &["clear", "reset"]
+    )]
+    clear: Option<ClearMode>,
+    #[structopt(
+        long = "notify",
+        help = "Show a desktop notification summarizing success/failure after each rebuild"
+    )]
+    notify: bool,
+    #[structopt(
+        long = "debounce",
+        help = "Debounce window in milliseconds; also how long an atomic remove+recreate (e.g. an editor saving by rename) is given to reappear before being treated as a real removal",
+        default_value = "500"
+    )]
+    debounce: u64,
+    #[structopt(
+        name = "filename",
+        help = "File or directory to watch",
+        required = true
+    )]
     filename: String,
     #[structopt(
         name = "command",
@@ -208,7 +822,12 @@ struct Opt {
 fn main() {
     let opt = Opt::from_args();
 
-    let rebuild_config = match RebuildConfig::new(opt.command, opt.verbatim) {
+    if opt.on_busy != OnBusy::Queue && !opt.run_async {
+        eprintln!("Error: --on-busy only has an effect together with --async");
+        exit(1);
+    }
+
+    let rebuild_config = match RebuildConfig::new(opt.command, opt.verbatim, opt.shell) {
         Ok(config) => config,
         Err(_) => {
             eprintln!("Syntax error: empty command isn't allowed");
@@ -218,15 +837,30 @@ fn main() {
 
     let (thread_tx, thread_rx) = channel();
     let manager_join_handle = prepare_manager_thread(thread_rx);
+    let rebuild_manager = Arc::new(RebuildManager::new(
+        opt.on_busy,
+        opt.stop_signal,
+        Duration::from_millis(opt.stop_timeout),
+        opt.clear,
+        opt.notify,
+    ));
 
     if opt.init {
         let path = PathBuf::from(&opt.filename);
-        do_rebuild(rebuild_config.set_filename(path), opt.run_async, &thread_tx);
+        do_rebuild(
+            rebuild_config.for_event(path, EventKind::Write),
+            opt.run_async,
+            opt.clear,
+            opt.notify,
+            &thread_tx,
+        );
     }
 
     let (tx, rx) = channel();
 
-    let mut watcher = match RecommendedWatcher::new(tx, Duration::from_millis(500)) {
+    let debounce = Duration::from_millis(opt.debounce);
+
+    let mut watcher = match RecommendedWatcher::new(tx, debounce) {
         Ok(watcher) => watcher,
         Err(why) => {
             eprintln!("Error: Failed to initialize watcher: {}", why);
@@ -234,22 +868,108 @@ fn main() {
         }
     };
 
-    if let Err(why) = watcher.watch(opt.filename, RecursiveMode::NonRecursive) {
+    // Canonicalize so later comparisons against the paths notify reports
+    // (which are canonical/absolute) actually match, even when the
+    // filename argument was given as a relative path.
+    let watch_path = PathBuf::from(&opt.filename);
+    let watch_path = watch_path.canonicalize().unwrap_or(watch_path);
+    let recursive_mode = if watch_path.is_dir() {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    let watch_root = if watch_path.is_dir() {
+        watch_path.clone()
+    } else {
+        watch_path
+            .parent()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."))
+    };
+    let path_filter = PathFilter::build(
+        &watch_root,
+        &opt.include,
+        &opt.exclude,
+        watch_path.is_dir(),
+    );
+
+    if let Err(why) = watcher.watch(&watch_path, recursive_mode) {
         eprintln!("Error: Failed to establish watch: {}", why);
         exit(1);
     };
 
+    let dispatch = |path: PathBuf, event: EventKind| {
+        if !path_filter.should_trigger(&path) {
+            return;
+        }
+        let config = rebuild_config.for_event(path, event);
+        if opt.run_async {
+            rebuild_manager.submit(config, &thread_tx);
+        } else {
+            do_rebuild(config, false, opt.clear, opt.notify, &thread_tx);
+        }
+    };
+
+    // Vim and other editors save by writing a new file and renaming it
+    // over the original, which shows up here as a Remove of the watched
+    // path followed almost immediately by its Create/Write. Give such a
+    // Remove `debounce` to be "confirmed" as a real removal before
+    // treating it as one, so we re-establish the watch and keep going
+    // instead of exiting.
+    let mut pending_removes: HashMap<PathBuf, Instant> = HashMap::new();
+
     loop {
-        match rx.recv() {
-            Ok(DebouncedEvent::Write(path)) => {
-                do_rebuild(rebuild_config.set_filename(path), opt.run_async, &thread_tx);
+        let now = Instant::now();
+        let timeout = pending_removes
+            .values()
+            .map(|deadline| deadline.saturating_duration_since(now))
+            .min()
+            .unwrap_or_else(|| Duration::from_secs(60 * 60));
+
+        match rx.recv_timeout(timeout) {
+            Ok(DebouncedEvent::Write(path)) | Ok(DebouncedEvent::Create(path))
+                if pending_removes.remove(&path).is_some() =>
+            {
+                if path == watch_path {
+                    if let Err(why) = watcher.watch(&path, recursive_mode) {
+                        eprintln!("Warning: Failed to re-establish watch: {}", why);
+                    }
+                }
+                dispatch(path, EventKind::Write);
             }
-            Ok(DebouncedEvent::Remove(_)) => {
-                println!("Error: Target file removed; exiting...");
-                break;
+            Ok(DebouncedEvent::Write(path)) => dispatch(path, EventKind::Write),
+            Ok(DebouncedEvent::Create(path)) => dispatch(path, EventKind::Create),
+            Ok(DebouncedEvent::Rename(_, to)) => dispatch(to, EventKind::Rename),
+            Ok(DebouncedEvent::Remove(path)) => {
+                pending_removes.insert(path, Instant::now() + debounce);
             }
             Ok(_) => continue,
-            Err(why) => eprintln!("Warning: Error watcing filesystem: {}", why),
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                let expired: Vec<PathBuf> = pending_removes
+                    .iter()
+                    .filter(|(_, deadline)| **deadline <= now)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+
+                let mut should_exit = false;
+                for path in expired {
+                    pending_removes.remove(&path);
+                    if !watch_path.is_dir() && path == watch_path {
+                        println!("Error: Target file removed; exiting...");
+                        should_exit = true;
+                    } else {
+                        dispatch(path, EventKind::Remove);
+                    }
+                }
+                if should_exit {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                eprintln!("Warning: Watcher channel disconnected");
+                break;
+            }
         }
     }
 
@@ -259,3 +979,91 @@ fn main() {
     thread_tx.send(ThreadHandleMessage::Finish).unwrap();
     manager_join_handle.join().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn on_busy_from_str_parses_known_values() {
+        assert_eq!(OnBusy::from_str("queue").unwrap(), OnBusy::Queue);
+        assert_eq!(OnBusy::from_str("do-nothing").unwrap(), OnBusy::DoNothing);
+        assert_eq!(OnBusy::from_str("restart").unwrap(), OnBusy::Restart);
+        assert_eq!(OnBusy::from_str("signal").unwrap(), OnBusy::Signal);
+        assert!(OnBusy::from_str("bogus").is_err());
+    }
+
+    #[test]
+    fn shell_from_str_parses_known_values() {
+        assert_eq!(Shell::from_str("unix").unwrap(), Shell::Unix);
+        assert_eq!(Shell::from_str("powershell").unwrap(), Shell::Powershell);
+        assert_eq!(Shell::from_str("cmd").unwrap(), Shell::Cmd);
+        assert_eq!(Shell::from_str("none").unwrap(), Shell::None);
+        assert!(Shell::from_str("bogus").is_err());
+    }
+
+    #[test]
+    fn clear_mode_from_str_parses_known_values() {
+        assert_eq!(ClearMode::from_str("clear").unwrap(), ClearMode::Clear);
+        assert_eq!(ClearMode::from_str("reset").unwrap(), ClearMode::Reset);
+        assert!(ClearMode::from_str("bogus").is_err());
+    }
+
+    #[test]
+    fn signal_from_str_rejects_unknown_names() {
+        assert!(Signal::from_str("SIGTERM").is_ok());
+        assert!(Signal::from_str("bogus").is_err());
+    }
+
+    #[test]
+    fn apply_event_substitutes_placeholders_and_sets_env() {
+        let mut cmd = SimpleCommand::new(
+            &["echo".to_string(), "{name}".to_string(), "{}".to_string()],
+            ProceedIf::Any,
+        )
+        .unwrap();
+
+        cmd.apply_event("src/main.rs", EventKind::Write);
+
+        assert_eq!(cmd.args, vec!["main.rs".to_string(), "src/main.rs".to_string()]);
+        assert_eq!(cmd.raw, "echo main.rs src/main.rs");
+        assert!(cmd
+            .env
+            .contains(&("REBUILD_NAME".to_string(), "main.rs".to_string())));
+        assert!(cmd
+            .env
+            .contains(&("REBUILD_EVENT".to_string(), "write".to_string())));
+    }
+
+    #[test]
+    fn path_filter_skips_gitignore_for_single_file_watch() {
+        let root = std::env::temp_dir().join(format!("rebuild-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+
+        // The .gitignore excludes *.log for a directory watch, but a
+        // single-file watch must never consult .gitignore/.ignore for
+        // the path the user explicitly asked to watch.
+        let dir_filter = PathFilter::build(&root, &[], &[], true);
+        let filter = PathFilter::build(&root, &[], &[], false);
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(!dir_filter.should_trigger(&root.join("ignored.log")));
+        assert!(filter.should_trigger(&root.join("ignored.log")));
+    }
+
+    #[test]
+    fn path_filter_honors_include_and_exclude() {
+        let root = std::env::temp_dir();
+        let filter = PathFilter::build(
+            &root,
+            &["*.rs".to_string()],
+            &["skip.rs".to_string()],
+            true,
+        );
+
+        assert!(filter.should_trigger(&root.join("main.rs")));
+        assert!(!filter.should_trigger(&root.join("skip.rs")));
+        assert!(!filter.should_trigger(&root.join("main.txt")));
+    }
+}